@@ -0,0 +1,1651 @@
+//! QP is a small image container: a header (magic, dimensions, color type,
+//! prefilter, compression method, and an optional stripe index) followed by
+//! compressed pixel data, either as one stream or as independently
+//! compressed row stripes (see [`encode_to_vec_tiled`]). The compression
+//! codec itself is pluggable (see [`CompressionMethod`]). This crate exposes
+//! buffer-based encode/decode APIs so embedders can use QP without touching
+//! the filesystem; `src/main.rs` is a thin CLI wrapper over these functions.
+
+use std::fmt;
+use std::io::{Read, Write};
+
+use brotli::enc::BrotliEncoderParams;
+use brotli::{BrotliCompress, BrotliDecompress};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+const MAGIC: &[u8; 4] = b"QPIM";
+
+/// No spatial prefilter; pixel samples are stored as-is before compression.
+const PREFILTER_NONE: u8 = 0;
+/// QOI-inspired run/index/delta prefilter, see [`qoi_prefilter_encode`].
+const PREFILTER_QOI: u8 = 1;
+/// PNG-style adaptive scanline prefilter, see [`scanline_filter_encode`].
+const PREFILTER_SCANLINE: u8 = 2;
+
+/// Size in bytes of the fixed portion of the encoded header: magic(4) +
+/// width(4) + height(4) + color_type(1) + prefilter(1) +
+/// compression_method(1) + tiled(1).
+///
+/// When `tiled` is nonzero, this is followed by a variable-length stripe
+/// index (see [`Header::parse`]); a non-tiled file's payload starts
+/// immediately after these bytes.
+const BASE_HEADER_LEN: usize = 16;
+
+/// The color layout a QP file's pixel data is stored in.
+///
+/// Mirrors a subset of `image::ColorType`, restricted to the variants QP
+/// knows how to pick automatically and reconstruct on decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorType {
+    L8,
+    La8,
+    Rgb8,
+    Rgba8,
+    L16,
+    Rgb16,
+    Rgba16,
+}
+
+impl ColorType {
+    /// Number of channels (samples per pixel) in this layout.
+    pub fn channels(self) -> usize {
+        match self {
+            ColorType::L8 | ColorType::L16 => 1,
+            ColorType::La8 => 2,
+            ColorType::Rgb8 | ColorType::Rgb16 => 3,
+            ColorType::Rgba8 | ColorType::Rgba16 => 4,
+        }
+    }
+
+    /// Whether this layout stores 8-bit samples (as opposed to 16-bit).
+    pub fn is_8_bit(self) -> bool {
+        matches!(
+            self,
+            ColorType::L8 | ColorType::La8 | ColorType::Rgb8 | ColorType::Rgba8
+        )
+    }
+
+    /// Bytes used to store a single channel sample (1 for 8-bit, 2 for 16-bit).
+    pub fn bytes_per_sample(self) -> usize {
+        if self.is_8_bit() {
+            1
+        } else {
+            2
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            ColorType::L8 => 0,
+            ColorType::La8 => 1,
+            ColorType::Rgb8 => 2,
+            ColorType::Rgba8 => 3,
+            ColorType::L16 => 4,
+            ColorType::Rgb16 => 5,
+            ColorType::Rgba16 => 6,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(ColorType::L8),
+            1 => Some(ColorType::La8),
+            2 => Some(ColorType::Rgb8),
+            3 => Some(ColorType::Rgba8),
+            4 => Some(ColorType::L16),
+            5 => Some(ColorType::Rgb16),
+            6 => Some(ColorType::Rgba16),
+            _ => None,
+        }
+    }
+}
+
+/// Which codec compresses a QP file's row (or stripe) payloads.
+///
+/// Byte values 4 and up are reserved for future codecs; decoding an unknown
+/// value fails with [`Error::UnsupportedCompressionMethod`] rather than
+/// guessing at how to interpret the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionMethod {
+    /// No compression; filtered pixel bytes are stored as-is.
+    None,
+    Brotli,
+    Deflate,
+    Zstd,
+}
+
+impl CompressionMethod {
+    fn to_byte(self) -> u8 {
+        match self {
+            CompressionMethod::None => 0,
+            CompressionMethod::Brotli => 1,
+            CompressionMethod::Deflate => 2,
+            CompressionMethod::Zstd => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(CompressionMethod::None),
+            1 => Some(CompressionMethod::Brotli),
+            2 => Some(CompressionMethod::Deflate),
+            3 => Some(CompressionMethod::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Tunables for [`encode_to_vec`]/[`encode_to_vec_tiled`]: which codec to
+/// use, and (for [`CompressionMethod::Brotli`]) how hard it should work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionOptions {
+    pub method: CompressionMethod,
+    /// Brotli compression level, `0..=11`. Ignored by other codecs.
+    pub quality: u8,
+    /// Brotli window size as a power of two, `10..=24`. Ignored by other
+    /// codecs.
+    pub lgwin: u8,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        CompressionOptions {
+            method: CompressionMethod::Brotli,
+            quality: 11,
+            lgwin: 24,
+        }
+    }
+}
+
+/// The parsed header of a QP file: everything needed to interpret the
+/// compressed payload that follows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub color_type: ColorType,
+    prefilter: u8,
+    compression_method: CompressionMethod,
+    /// Row height of each stripe, or 0 if the payload is a single stream
+    /// covering the whole image (see [`encode_to_vec_tiled`]).
+    tile_rows: u32,
+    /// Compressed byte length of each stripe, in row order. Empty unless
+    /// `tile_rows != 0`.
+    stripe_lengths: Vec<u32>,
+}
+
+impl Header {
+    /// Whether this file's payload is a sequence of independently
+    /// Brotli-compressed row stripes rather than one single stream.
+    pub fn is_tiled(&self) -> bool {
+        self.tile_rows != 0
+    }
+
+    /// Row height of each stripe. Only meaningful when [`Header::is_tiled`].
+    pub fn tile_rows(&self) -> u32 {
+        self.tile_rows
+    }
+
+    /// The codec used to compress this file's payload.
+    pub fn compression_method(&self) -> CompressionMethod {
+        self.compression_method
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(BASE_HEADER_LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&self.width.to_be_bytes());
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.push(self.color_type.to_byte());
+        bytes.push(self.prefilter);
+        bytes.push(self.compression_method.to_byte());
+        bytes.push(self.is_tiled() as u8);
+
+        if self.is_tiled() {
+            bytes.extend_from_slice(&self.tile_rows.to_be_bytes());
+            bytes.extend_from_slice(&(self.stripe_lengths.len() as u32).to_be_bytes());
+            for len in &self.stripe_lengths {
+                bytes.extend_from_slice(&len.to_be_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    /// Parse a header from the start of `data`, returning it along with the
+    /// number of bytes it occupied (the payload starts immediately after).
+    fn parse(data: &[u8]) -> Result<(Self, usize), Error> {
+        if data.len() < BASE_HEADER_LEN {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "QP file shorter than its header",
+            )));
+        }
+        if &data[0..4] != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+
+        let width = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        let height = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+        let color_type =
+            ColorType::from_byte(data[12]).ok_or(Error::UnsupportedColorType(data[12]))?;
+        let prefilter = data[13];
+        let compression_method = CompressionMethod::from_byte(data[14])
+            .ok_or(Error::UnsupportedCompressionMethod(data[14]))?;
+        let tiled = data[15] != 0;
+
+        if !matches!(
+            prefilter,
+            PREFILTER_NONE | PREFILTER_QOI | PREFILTER_SCANLINE
+        ) {
+            return Err(Error::UnsupportedPrefilter(prefilter));
+        }
+
+        let mut pos = BASE_HEADER_LEN;
+        let (tile_rows, stripe_lengths) = if tiled {
+            if data.len() < pos + 8 {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "QP file shorter than its stripe index",
+                )));
+            }
+            let tile_rows =
+                u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+            let stripe_count =
+                u32::from_be_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+                    as usize;
+            pos += 8;
+
+            if data.len() < pos + stripe_count * 4 {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "QP file shorter than its stripe index",
+                )));
+            }
+            let mut stripe_lengths = Vec::with_capacity(stripe_count);
+            for _ in 0..stripe_count {
+                stripe_lengths.push(u32::from_be_bytes([
+                    data[pos],
+                    data[pos + 1],
+                    data[pos + 2],
+                    data[pos + 3],
+                ]));
+                pos += 4;
+            }
+
+            if tile_rows == 0 {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "QP file has the tiled flag set but a tile_rows of 0",
+                )));
+            }
+            let expected_stripe_count =
+                stripe_row_counts(height as usize, tile_rows as usize).len();
+            if stripe_lengths.len() != expected_stripe_count {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "QP file stripe index has {} entries, expected {} for a {}-row tiling of a {}-row image",
+                        stripe_lengths.len(),
+                        expected_stripe_count,
+                        tile_rows,
+                        height
+                    ),
+                )));
+            }
+
+            (tile_rows, stripe_lengths)
+        } else {
+            (0, Vec::new())
+        };
+
+        Ok((
+            Header {
+                width,
+                height,
+                color_type,
+                prefilter,
+                compression_method,
+                tile_rows,
+                stripe_lengths,
+            },
+            pos,
+        ))
+    }
+
+    /// Number of raw interleaved pixel bytes this header describes.
+    pub fn raw_len(&self) -> usize {
+        (self.width as usize)
+            * (self.height as usize)
+            * self.color_type.channels()
+            * self.color_type.bytes_per_sample()
+    }
+}
+
+/// Errors produced by QP's encode/decode paths.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    InvalidMagic,
+    UnsupportedColorType(u8),
+    UnsupportedPrefilter(u8),
+    UnsupportedCompressionMethod(u8),
+    ImageReconstructionFailed,
+    /// A caller-provided output buffer (to `encode_to_buf`/`decode_to_buf`)
+    /// was too small to hold the result.
+    OutputBufferTooSmall {
+        size: usize,
+        required: usize,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::InvalidMagic => write!(f, "Invalid QP image file: incorrect magic number."),
+            Error::UnsupportedColorType(b) => write!(f, "Unsupported color type byte: {}", b),
+            Error::UnsupportedPrefilter(b) => write!(f, "Unsupported prefilter byte: {}", b),
+            Error::UnsupportedCompressionMethod(b) => {
+                write!(f, "Unsupported compression method: {}", b)
+            }
+            Error::ImageReconstructionFailed => {
+                write!(f, "Failed to reconstruct image from pixel data.")
+            }
+            Error::OutputBufferTooSmall { size, required } => write!(
+                f,
+                "Output buffer too small: got {} bytes, need {} bytes",
+                size, required
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Encode raw interleaved pixel samples to a QP-format byte vector, using
+/// the default [`CompressionOptions`] (Brotli at quality 11, lgwin 24). See
+/// [`encode_to_vec_with_options`] to pick a different codec or quality.
+pub fn encode_to_vec(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+) -> Result<Vec<u8>, Error> {
+    encode_to_vec_with_options(
+        pixels,
+        width,
+        height,
+        color_type,
+        CompressionOptions::default(),
+    )
+}
+
+/// Encode raw interleaved pixel samples to a QP-format byte vector with an
+/// explicit choice of compression codec and (for Brotli) quality/window
+/// settings.
+pub fn encode_to_vec_with_options(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    options: CompressionOptions,
+) -> Result<Vec<u8>, Error> {
+    // Both prefilters only understand 8-bit samples, so 16-bit images are
+    // passed through untouched; otherwise try QOI and the scanline filter
+    // and keep whichever one actually compresses smaller for this image.
+    let (prefilter, compressed_data) = if color_type.is_8_bit() {
+        choose_prefilter(pixels, width as usize, color_type.channels(), options)?
+    } else {
+        (PREFILTER_NONE, compress_bytes(pixels, options)?)
+    };
+
+    let header = Header {
+        width,
+        height,
+        color_type,
+        prefilter,
+        compression_method: options.method,
+        tile_rows: 0,
+        stripe_lengths: Vec::new(),
+    };
+
+    let mut out = Vec::with_capacity(BASE_HEADER_LEN + compressed_data.len());
+    out.extend_from_slice(&header.to_bytes());
+    out.extend_from_slice(&compressed_data);
+    Ok(out)
+}
+
+/// Encode into a caller-provided buffer, returning the number of bytes
+/// written. Fails with [`Error::OutputBufferTooSmall`] rather than
+/// truncating if `out` isn't big enough.
+pub fn encode_to_buf(
+    out: &mut [u8],
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+) -> Result<usize, Error> {
+    let encoded = encode_to_vec(pixels, width, height, color_type)?;
+    if out.len() < encoded.len() {
+        return Err(Error::OutputBufferTooSmall {
+            size: out.len(),
+            required: encoded.len(),
+        });
+    }
+    out[..encoded.len()].copy_from_slice(&encoded);
+    Ok(encoded.len())
+}
+
+/// Encode raw interleaved pixel samples to QP format as independently
+/// compressed horizontal stripes of `tile_rows` rows each, writing a
+/// stripe index into the header so [`decode_to_vec`] can decompress stripes
+/// concurrently (behind the `parallel` feature, as oxipng does with PNG
+/// filtering) and stitch the rows back in order.
+///
+/// Each stripe is filtered independently, so row 0 of every stripe after
+/// the first is treated as if it had no row above it; this costs a little
+/// compression ratio at stripe boundaries in exchange for stripes that can
+/// be encoded and decoded with no cross-stripe dependency.
+pub fn encode_to_vec_tiled(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    tile_rows: u32,
+) -> Result<Vec<u8>, Error> {
+    encode_to_vec_tiled_with_options(
+        pixels,
+        width,
+        height,
+        color_type,
+        tile_rows,
+        CompressionOptions::default(),
+    )
+}
+
+/// Like [`encode_to_vec_tiled`], with an explicit choice of compression
+/// codec and (for Brotli) quality/window settings.
+pub fn encode_to_vec_tiled_with_options(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    tile_rows: u32,
+    options: CompressionOptions,
+) -> Result<Vec<u8>, Error> {
+    let tile_rows = tile_rows.max(1);
+    let channels = color_type.channels();
+    let row_bytes = width as usize * channels * color_type.bytes_per_sample();
+    let row_counts = stripe_row_counts(height as usize, tile_rows as usize);
+
+    // The header carries one prefilter tag for the whole file, so the
+    // choice can't be made per stripe; comparing compressed sizes over the
+    // entire image up front would also serialize a full extra encode pass
+    // ahead of the (parallel) per-stripe compression below, defeating the
+    // point of tiling. Instead the decision is made from the first stripe
+    // alone, which is representative enough in practice and costs a small
+    // fraction of the full encode.
+    let prefilter = if color_type.is_8_bit() {
+        let sample_len = row_counts.first().copied().unwrap_or(0) * row_bytes;
+        choose_prefilter(&pixels[..sample_len], width as usize, channels, options)?.0
+    } else {
+        PREFILTER_NONE
+    };
+
+    let mut filtered_stripes = Vec::with_capacity(row_counts.len());
+    let mut row = 0usize;
+    for &rows in &row_counts {
+        let start = row * row_bytes;
+        let end = start + rows * row_bytes;
+        let stripe_pixels = &pixels[start..end];
+        filtered_stripes.push(apply_prefilter(prefilter, stripe_pixels, width as usize, channels));
+        row += rows;
+    }
+
+    let compressed_stripes = compress_stripes(&filtered_stripes, options)?;
+
+    let header = Header {
+        width,
+        height,
+        color_type,
+        prefilter,
+        compression_method: options.method,
+        tile_rows,
+        stripe_lengths: compressed_stripes.iter().map(|s| s.len() as u32).collect(),
+    };
+
+    let mut out = header.to_bytes();
+    for stripe in &compressed_stripes {
+        out.extend_from_slice(stripe);
+    }
+    Ok(out)
+}
+
+/// Filter 8-bit interleaved pixel samples with both prefilters and keep
+/// whichever compresses smaller, returning its tag along with the already-
+/// compressed bytes. QOI's run/index/delta ops tend to win on flat or
+/// palette-like content, while the scanline predictor wins on photographic
+/// and gradient content, so neither is a safe default on its own.
+fn choose_prefilter(
+    pixels: &[u8],
+    width: usize,
+    channels: usize,
+    options: CompressionOptions,
+) -> Result<(u8, Vec<u8>), Error> {
+    let qoi_compressed = compress_bytes(&qoi_prefilter_encode(pixels, channels), options)?;
+    let scanline_compressed =
+        compress_bytes(&scanline_filter_encode(pixels, width, channels), options)?;
+
+    Ok(if qoi_compressed.len() <= scanline_compressed.len() {
+        (PREFILTER_QOI, qoi_compressed)
+    } else {
+        (PREFILTER_SCANLINE, scanline_compressed)
+    })
+}
+
+/// Apply the named prefilter to interleaved pixel samples, as chosen by
+/// [`choose_prefilter`] for an earlier stripe or the image as a whole.
+fn apply_prefilter(prefilter: u8, pixels: &[u8], width: usize, channels: usize) -> Vec<u8> {
+    match prefilter {
+        PREFILTER_QOI => qoi_prefilter_encode(pixels, channels),
+        PREFILTER_SCANLINE => scanline_filter_encode(pixels, width, channels),
+        _ => pixels.to_vec(),
+    }
+}
+
+/// Compress one buffer with the codec named in `options`.
+fn compress_bytes(data: &[u8], options: CompressionOptions) -> Result<Vec<u8>, Error> {
+    match options.method {
+        CompressionMethod::None => Ok(data.to_vec()),
+        CompressionMethod::Brotli => {
+            let params = BrotliEncoderParams {
+                quality: options.quality.min(11) as i32,
+                lgwin: options.lgwin.clamp(10, 24) as i32,
+                ..Default::default()
+            };
+            let mut out = Vec::new();
+            BrotliCompress(&mut &data[..], &mut out, &params)?;
+            Ok(out)
+        }
+        CompressionMethod::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        CompressionMethod::Zstd => zstd::stream::encode_all(data, 19).map_err(Error::Io),
+    }
+}
+
+/// Decompress one buffer with the named codec.
+fn decompress_bytes(data: &[u8], method: CompressionMethod) -> Result<Vec<u8>, Error> {
+    match method {
+        CompressionMethod::None => Ok(data.to_vec()),
+        CompressionMethod::Brotli => {
+            let mut out = Vec::new();
+            BrotliDecompress(&mut &data[..], &mut out)?;
+            Ok(out)
+        }
+        CompressionMethod::Deflate => {
+            let mut out = Vec::new();
+            DeflateDecoder::new(data).read_to_end(&mut out)?;
+            Ok(out)
+        }
+        CompressionMethod::Zstd => zstd::stream::decode_all(data).map_err(Error::Io),
+    }
+}
+
+/// Decode a QP file's bytes into its header and raw interleaved pixel samples.
+pub fn decode_to_vec(data: &[u8]) -> Result<(Header, Vec<u8>), Error> {
+    let (header, header_len) = Header::parse(data)?;
+    let payload = &data[header_len..];
+
+    let raw_data = if header.is_tiled() {
+        decode_tiled(&header, payload)?
+    } else {
+        let decompressed_data = decompress_bytes(payload, header.compression_method)?;
+        unfilter_stripe(
+            &decompressed_data,
+            header.prefilter,
+            header.width as usize,
+            header.height as usize,
+            header.color_type.channels(),
+        )?
+    };
+
+    Ok((header, raw_data))
+}
+
+/// Invert whichever prefilter was applied to one contiguous block of rows
+/// (a whole image, or a single stripe of one).
+fn unfilter_stripe(
+    data: &[u8],
+    prefilter: u8,
+    width: usize,
+    height: usize,
+    channels: usize,
+) -> Result<Vec<u8>, Error> {
+    let pixel_count = width * height;
+    match prefilter {
+        PREFILTER_NONE => Ok(data.to_vec()),
+        PREFILTER_QOI => qoi_prefilter_decode(data, channels, pixel_count),
+        PREFILTER_SCANLINE => scanline_filter_decode(data, width, height, channels),
+        other => Err(Error::UnsupportedPrefilter(other)),
+    }
+}
+
+/// Decompress and un-filter a tiled payload: each stripe was compressed
+/// independently, so each is decompressed independently (in parallel, behind
+/// the `parallel` feature) and the results are stitched back in row order.
+fn decode_tiled(header: &Header, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    let channels = header.color_type.channels();
+    let width = header.width as usize;
+    let tile_rows = header.tile_rows as usize;
+
+    let overrun = || {
+        Error::Io(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "QP file stripe index overruns its payload",
+        ))
+    };
+
+    let mut stripe_slices = Vec::with_capacity(header.stripe_lengths.len());
+    let mut offset = 0usize;
+    for &len in &header.stripe_lengths {
+        let len = len as usize;
+        let end = offset.checked_add(len).ok_or_else(overrun)?;
+        if end > payload.len() {
+            return Err(overrun());
+        }
+        stripe_slices.push(&payload[offset..end]);
+        offset = end;
+    }
+
+    let row_counts: Vec<usize> = stripe_row_counts(header.height as usize, tile_rows);
+    let decompressed_stripes = decompress_stripes(&stripe_slices, header.compression_method)?;
+
+    let mut out = Vec::with_capacity(header.raw_len());
+    for (decompressed, rows) in decompressed_stripes.into_iter().zip(row_counts) {
+        out.extend_from_slice(&unfilter_stripe(
+            &decompressed,
+            header.prefilter,
+            width,
+            rows,
+            channels,
+        )?);
+    }
+    Ok(out)
+}
+
+/// Row counts of every stripe of a `tile_rows`-high tiling of an image that
+/// is `height` rows tall (the last stripe is shorter when it doesn't divide
+/// evenly).
+fn stripe_row_counts(height: usize, tile_rows: usize) -> Vec<usize> {
+    let mut counts = Vec::new();
+    let mut row = 0;
+    while row < height {
+        let rows_in_stripe = tile_rows.min(height - row);
+        counts.push(rows_in_stripe);
+        row += rows_in_stripe;
+    }
+    counts
+}
+
+/// Compress each stripe independently with the codec named in `options`,
+/// using rayon across threads when the `parallel` feature is enabled.
+fn compress_stripes(
+    stripes: &[Vec<u8>],
+    options: CompressionOptions,
+) -> Result<Vec<Vec<u8>>, Error> {
+    #[cfg(feature = "parallel")]
+    {
+        stripes
+            .par_iter()
+            .map(|stripe| compress_bytes(stripe, options))
+            .collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        stripes
+            .iter()
+            .map(|stripe| compress_bytes(stripe, options))
+            .collect()
+    }
+}
+
+/// Decompress each stripe independently with the named codec, using rayon
+/// across threads when the `parallel` feature is enabled.
+fn decompress_stripes(stripes: &[&[u8]], method: CompressionMethod) -> Result<Vec<Vec<u8>>, Error> {
+    #[cfg(feature = "parallel")]
+    {
+        stripes
+            .par_iter()
+            .map(|stripe| decompress_bytes(stripe, method))
+            .collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        stripes
+            .iter()
+            .map(|stripe| decompress_bytes(stripe, method))
+            .collect()
+    }
+}
+
+/// Decode into a caller-provided buffer, returning the parsed header. Fails
+/// with [`Error::OutputBufferTooSmall`] rather than truncating if `out`
+/// isn't big enough to hold the raw pixel data.
+pub fn decode_to_buf(out: &mut [u8], data: &[u8]) -> Result<Header, Error> {
+    let (header, raw_data) = decode_to_vec(data)?;
+    if out.len() < raw_data.len() {
+        return Err(Error::OutputBufferTooSmall {
+            size: out.len(),
+            required: raw_data.len(),
+        });
+    }
+    out[..raw_data.len()].copy_from_slice(&raw_data);
+    Ok(header)
+}
+
+/// Decode a (possibly truncated or corrupted) QP file on a best-effort
+/// basis, salvaging whatever pixel data can be recovered and zero-filling
+/// the rest rather than failing the whole decode. Returns the header, the
+/// raw pixel buffer, and the number of pixels actually recovered.
+pub fn decode_to_vec_lossy(data: &[u8]) -> Result<(Header, Vec<u8>, usize), Error> {
+    let (header, header_len) = Header::parse(data)?;
+    let payload = &data[header_len..];
+
+    let (raw_data, pixels_recovered) = if header.is_tiled() {
+        decode_tiled_lossy(&header, payload)
+    } else {
+        decode_stripe_lossy(
+            payload,
+            header.compression_method,
+            header.prefilter,
+            header.width as usize,
+            header.height as usize,
+            header.color_type,
+        )
+    };
+
+    Ok((header, raw_data, pixels_recovered))
+}
+
+/// Decompress via a streaming reader so a truncated/corrupted tail just
+/// ends the stream early instead of failing the whole call.
+fn decompress_tolerant(compressed: &[u8], method: CompressionMethod) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    macro_rules! read_all {
+        ($reader:expr) => {{
+            let mut reader = $reader;
+            loop {
+                match reader.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => out.extend_from_slice(&chunk[..n]),
+                    Err(_) => break,
+                }
+            }
+        }};
+    }
+
+    match method {
+        CompressionMethod::None => out.extend_from_slice(compressed),
+        CompressionMethod::Brotli => read_all!(brotli::Decompressor::new(compressed, 4096)),
+        CompressionMethod::Deflate => read_all!(DeflateDecoder::new(compressed)),
+        CompressionMethod::Zstd => {
+            if let Ok(reader) = zstd::stream::read::Decoder::new(compressed) {
+                read_all!(reader)
+            }
+        }
+    }
+
+    out
+}
+
+/// Best-effort decode of one contiguous block of rows: decompress
+/// tolerantly via the streaming reader, then invert whatever prefilter was
+/// used, tolerantly as well.
+fn decode_stripe_lossy(
+    compressed: &[u8],
+    method: CompressionMethod,
+    prefilter: u8,
+    width: usize,
+    height: usize,
+    color_type: ColorType,
+) -> (Vec<u8>, usize) {
+    let channels = color_type.channels();
+    let pixel_count = width * height;
+    let total_bytes = width * height * channels * color_type.bytes_per_sample();
+
+    let decompressed_data = decompress_tolerant(compressed, method);
+
+    match prefilter {
+        PREFILTER_NONE => {
+            let recovered_bytes = decompressed_data.len().min(total_bytes);
+            let mut raw = vec![0u8; total_bytes];
+            raw[..recovered_bytes].copy_from_slice(&decompressed_data[..recovered_bytes]);
+            let bytes_per_pixel = channels * color_type.bytes_per_sample();
+            (raw, recovered_bytes / bytes_per_pixel.max(1))
+        }
+        PREFILTER_QOI => qoi_prefilter_decode_lossy(&decompressed_data, channels, pixel_count),
+        PREFILTER_SCANLINE => {
+            scanline_filter_decode_lossy(&decompressed_data, width, height, channels)
+        }
+        _ => (vec![0u8; total_bytes], 0),
+    }
+}
+
+/// Best-effort decode of a tiled payload: stripes are decoded independently
+/// in row order, and as soon as one comes up short the rest are zero-filled
+/// rather than guessed at, since a later stripe's compressed stream can't be
+/// entered without the one before it.
+fn decode_tiled_lossy(header: &Header, payload: &[u8]) -> (Vec<u8>, usize) {
+    let channels = header.color_type.channels();
+    let width = header.width as usize;
+    let row_counts = stripe_row_counts(header.height as usize, header.tile_rows as usize);
+
+    let mut raw_data = Vec::with_capacity(header.raw_len());
+    let mut pixels_recovered = 0;
+    let mut offset = 0usize;
+    let mut stripes_truncated = false;
+
+    for (stripe_index, &rows) in row_counts.iter().enumerate() {
+        let stripe_len = header
+            .stripe_lengths
+            .get(stripe_index)
+            .copied()
+            .unwrap_or(0) as usize;
+
+        if stripes_truncated || offset + stripe_len > payload.len() {
+            let stripe_bytes = rows * width * channels * header.color_type.bytes_per_sample();
+            raw_data.resize(raw_data.len() + stripe_bytes, 0);
+            stripes_truncated = true;
+            continue;
+        }
+
+        let compressed = &payload[offset..offset + stripe_len];
+        offset += stripe_len;
+
+        let (stripe_raw, stripe_recovered) = decode_stripe_lossy(
+            compressed,
+            header.compression_method,
+            header.prefilter,
+            width,
+            rows,
+            header.color_type,
+        );
+        let stripe_complete = stripe_recovered == rows * width;
+
+        raw_data.extend_from_slice(&stripe_raw);
+        pixels_recovered += stripe_recovered;
+
+        if !stripe_complete {
+            stripes_truncated = true;
+        }
+    }
+
+    (raw_data, pixels_recovered)
+}
+
+// --- QOI-inspired spatial prefilter -----------------------------------
+//
+// Brotli compresses raw interleaved pixel bytes reasonably well, but it
+// has no notion of "this pixel looks like the one eight bytes ago". The
+// prefilter below borrows QOI's trick of turning the 2D pixel stream into
+// a 1D op stream (runs, hash-table hits, small deltas, literals) that
+// Brotli's LZ77 + entropy stage can chew on far more effectively.
+//
+// The ops only operate on 8-bit samples; 16-bit color types skip the
+// prefilter (callers only reach for it with `channels() <= 4` 8-bit data).
+
+const QOI_OP_RUN: u8 = 0x00;
+const QOI_OP_INDEX: u8 = 0x01;
+const QOI_OP_DIFF: u8 = 0x02;
+const QOI_OP_LUMA: u8 = 0x03;
+const QOI_OP_LITERAL: u8 = 0x04;
+
+const QOI_HASH_TABLE_SIZE: usize = 64;
+
+/// Expand a pixel with `channels` 8-bit samples to an (r, g, b, a) tuple.
+fn expand_to_rgba(pixel: &[u8], channels: usize) -> [u8; 4] {
+    match channels {
+        1 => [pixel[0], pixel[0], pixel[0], 255],
+        2 => [pixel[0], pixel[0], pixel[0], pixel[1]],
+        3 => [pixel[0], pixel[1], pixel[2], 255],
+        4 => [pixel[0], pixel[1], pixel[2], pixel[3]],
+        other => panic!("unsupported channel count for QOI prefilter: {}", other),
+    }
+}
+
+/// The inverse of [`expand_to_rgba`]: drop back to the native channel count.
+fn narrow_from_rgba(rgba: [u8; 4], channels: usize) -> Vec<u8> {
+    match channels {
+        1 => vec![rgba[0]],
+        2 => vec![rgba[0], rgba[3]],
+        3 => vec![rgba[0], rgba[1], rgba[2]],
+        4 => rgba.to_vec(),
+        other => panic!("unsupported channel count for QOI prefilter: {}", other),
+    }
+}
+
+fn qoi_hash(rgba: [u8; 4]) -> usize {
+    let [r, g, b, a] = rgba;
+    (r as usize)
+        .wrapping_mul(3)
+        .wrapping_add((g as usize).wrapping_mul(5))
+        .wrapping_add((b as usize).wrapping_mul(7))
+        .wrapping_add((a as usize).wrapping_mul(11))
+        % QOI_HASH_TABLE_SIZE
+}
+
+/// Apply the QOI-inspired spatial prefilter to interleaved pixel samples.
+fn qoi_prefilter_encode(pixels: &[u8], channels: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(pixels.len());
+    let mut table = [[0u8; 4]; QOI_HASH_TABLE_SIZE];
+    let mut prev = [0u8, 0, 0, 255]; // seed: opaque black
+
+    let mut i = 0;
+    let pixel_bytes = pixels.chunks_exact(channels);
+    let pixels: Vec<[u8; 4]> = pixel_bytes.map(|p| expand_to_rgba(p, channels)).collect();
+
+    while i < pixels.len() {
+        let current = pixels[i];
+
+        if current == prev {
+            let mut run = 1usize;
+            while i + run < pixels.len() && pixels[i + run] == prev && run < 256 {
+                run += 1;
+            }
+            out.push(QOI_OP_RUN);
+            out.push((run - 1) as u8);
+            i += run;
+            continue;
+        }
+
+        let hash = qoi_hash(current);
+        if table[hash] == current {
+            out.push(QOI_OP_INDEX);
+            out.push(hash as u8);
+        } else if let Some(diff_byte) = try_small_diff(prev, current, channels) {
+            out.push(QOI_OP_DIFF);
+            out.push(diff_byte);
+        } else if channels >= 3 {
+            if let Some((dg, drb)) = try_luma(prev, current) {
+                out.push(QOI_OP_LUMA);
+                out.push(dg);
+                out.push(drb);
+            } else {
+                out.push(QOI_OP_LITERAL);
+                out.extend_from_slice(&narrow_from_rgba(current, channels));
+            }
+        } else {
+            out.push(QOI_OP_LITERAL);
+            out.extend_from_slice(&narrow_from_rgba(current, channels));
+        }
+
+        table[hash] = current;
+        prev = current;
+        i += 1;
+    }
+
+    out
+}
+
+/// Try to pack each channel's delta from `prev` to `current` into one byte,
+/// two bits per channel, each representing a delta in `-2..=1`.
+fn try_small_diff(prev: [u8; 4], current: [u8; 4], channels: usize) -> Option<u8> {
+    let mut byte = 0u8;
+    for c in 0..channels {
+        let delta = current[c].wrapping_sub(prev[c]) as i8;
+        if !(-2..=1).contains(&delta) {
+            return None;
+        }
+        let bits = (delta + 2) as u8; // 0..=3
+        byte |= bits << (c * 2);
+    }
+    Some(byte)
+}
+
+fn unpack_small_diff(prev: [u8; 4], byte: u8, channels: usize) -> [u8; 4] {
+    let mut out = prev;
+    for c in 0..channels {
+        let bits = (byte >> (c * 2)) & 0b11;
+        let delta = bits as i8 - 2;
+        out[c] = prev[c].wrapping_add(delta as u8);
+    }
+    out
+}
+
+/// Try QOI's luma-biased delta: a green delta in `-32..=31`, plus red/blue
+/// deltas relative to the green delta in `-8..=7`. Alpha must be unchanged.
+fn try_luma(prev: [u8; 4], current: [u8; 4]) -> Option<(u8, u8)> {
+    if current[3] != prev[3] {
+        return None;
+    }
+
+    let dg = current[1].wrapping_sub(prev[1]) as i8;
+    if !(-32..=31).contains(&dg) {
+        return None;
+    }
+
+    let dr_dg = (current[0].wrapping_sub(prev[0]) as i8).wrapping_sub(dg);
+    let db_dg = (current[2].wrapping_sub(prev[2]) as i8).wrapping_sub(dg);
+    if !(-8..=7).contains(&dr_dg) || !(-8..=7).contains(&db_dg) {
+        return None;
+    }
+
+    let dg_byte = (dg + 32) as u8; // 0..=63
+    let drb_byte = (((dr_dg + 8) as u8) << 4) | ((db_dg + 8) as u8);
+    Some((dg_byte, drb_byte))
+}
+
+fn unpack_luma(prev: [u8; 4], dg_byte: u8, drb_byte: u8) -> [u8; 4] {
+    let dg = dg_byte as i8 - 32;
+    let dr_dg = ((drb_byte >> 4) & 0x0F) as i8 - 8;
+    let db_dg = (drb_byte & 0x0F) as i8 - 8;
+
+    [
+        prev[0].wrapping_add((dg + dr_dg) as u8),
+        prev[1].wrapping_add(dg as u8),
+        prev[2].wrapping_add((dg + db_dg) as u8),
+        prev[3],
+    ]
+}
+
+/// Invert [`qoi_prefilter_encode`], reconstructing `pixel_count` pixels of
+/// `channels` interleaved 8-bit samples. Fails with [`Error::Io`] rather
+/// than panicking if the op stream runs out of bytes mid-op or contains an
+/// unrecognized tag, so a corrupt `.qp` file can't crash the non-lossy
+/// decode path (see [`qoi_prefilter_decode_lossy`] for the tolerant twin
+/// used by `--lossy`).
+fn qoi_prefilter_decode(
+    data: &[u8],
+    channels: usize,
+    pixel_count: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::with_capacity(pixel_count * channels);
+    let mut table = [[0u8; 4]; QOI_HASH_TABLE_SIZE];
+    let mut prev = [0u8, 0, 0, 255]; // seed: opaque black
+
+    let truncated = || {
+        Error::Io(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "corrupt QOI prefilter stream: ran out of data",
+        ))
+    };
+
+    let mut pos = 0;
+    let mut produced = 0;
+
+    while produced < pixel_count {
+        let tag = *data.get(pos).ok_or_else(truncated)?;
+        pos += 1;
+
+        match tag {
+            QOI_OP_RUN => {
+                let run = *data.get(pos).ok_or_else(truncated)? as usize + 1;
+                pos += 1;
+                for _ in 0..run {
+                    out.extend_from_slice(&narrow_from_rgba(prev, channels));
+                }
+                table[qoi_hash(prev)] = prev;
+                produced += run;
+            }
+            QOI_OP_INDEX => {
+                let index = *data.get(pos).ok_or_else(truncated)? as usize % QOI_HASH_TABLE_SIZE;
+                pos += 1;
+                let pixel = table[index];
+                out.extend_from_slice(&narrow_from_rgba(pixel, channels));
+                prev = pixel;
+                produced += 1;
+            }
+            QOI_OP_DIFF => {
+                let byte = *data.get(pos).ok_or_else(truncated)?;
+                pos += 1;
+                let pixel = unpack_small_diff(prev, byte, channels);
+                out.extend_from_slice(&narrow_from_rgba(pixel, channels));
+                table[qoi_hash(pixel)] = pixel;
+                prev = pixel;
+                produced += 1;
+            }
+            QOI_OP_LUMA => {
+                let dg_byte = *data.get(pos).ok_or_else(truncated)?;
+                let drb_byte = *data.get(pos + 1).ok_or_else(truncated)?;
+                pos += 2;
+                let pixel = unpack_luma(prev, dg_byte, drb_byte);
+                out.extend_from_slice(&narrow_from_rgba(pixel, channels));
+                table[qoi_hash(pixel)] = pixel;
+                prev = pixel;
+                produced += 1;
+            }
+            QOI_OP_LITERAL => {
+                let raw = data.get(pos..pos + channels).ok_or_else(truncated)?;
+                pos += channels;
+                let pixel = expand_to_rgba(raw, channels);
+                out.extend_from_slice(raw);
+                table[qoi_hash(pixel)] = pixel;
+                prev = pixel;
+                produced += 1;
+            }
+            other => {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("corrupt QOI prefilter stream: unknown op {}", other),
+                )))
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Truncation-tolerant variant of [`qoi_prefilter_decode`]: stops as soon
+/// as the op stream runs out of bytes to decode a whole op, zero-filling
+/// the remaining pixels. Returns the reconstructed buffer and the number
+/// of pixels recovered.
+fn qoi_prefilter_decode_lossy(
+    data: &[u8],
+    channels: usize,
+    pixel_count: usize,
+) -> (Vec<u8>, usize) {
+    let mut out = vec![0u8; pixel_count * channels];
+    let mut table = [[0u8; 4]; QOI_HASH_TABLE_SIZE];
+    let mut prev = [0u8, 0, 0, 255]; // seed: opaque black
+
+    let mut pos = 0;
+    let mut produced = 0;
+
+    while produced < pixel_count && pos < data.len() {
+        let tag = data[pos];
+        let op_len = match tag {
+            QOI_OP_RUN | QOI_OP_INDEX | QOI_OP_DIFF => 2,
+            QOI_OP_LUMA => 3,
+            QOI_OP_LITERAL => 1 + channels,
+            _ => break,
+        };
+        if pos + op_len > data.len() {
+            break;
+        }
+        pos += 1;
+
+        let pixels_written: usize;
+        match tag {
+            QOI_OP_RUN => {
+                let run = (data[pos] as usize + 1).min(pixel_count - produced);
+                pos += 1;
+                for i in 0..run {
+                    out[(produced + i) * channels..(produced + i + 1) * channels]
+                        .copy_from_slice(&narrow_from_rgba(prev, channels));
+                }
+                table[qoi_hash(prev)] = prev;
+                pixels_written = run;
+            }
+            QOI_OP_INDEX => {
+                let index = data[pos] as usize % QOI_HASH_TABLE_SIZE;
+                pos += 1;
+                let pixel = table[index];
+                out[produced * channels..(produced + 1) * channels]
+                    .copy_from_slice(&narrow_from_rgba(pixel, channels));
+                prev = pixel;
+                pixels_written = 1;
+            }
+            QOI_OP_DIFF => {
+                let byte = data[pos];
+                pos += 1;
+                let pixel = unpack_small_diff(prev, byte, channels);
+                out[produced * channels..(produced + 1) * channels]
+                    .copy_from_slice(&narrow_from_rgba(pixel, channels));
+                table[qoi_hash(pixel)] = pixel;
+                prev = pixel;
+                pixels_written = 1;
+            }
+            QOI_OP_LUMA => {
+                let dg_byte = data[pos];
+                let drb_byte = data[pos + 1];
+                pos += 2;
+                let pixel = unpack_luma(prev, dg_byte, drb_byte);
+                out[produced * channels..(produced + 1) * channels]
+                    .copy_from_slice(&narrow_from_rgba(pixel, channels));
+                table[qoi_hash(pixel)] = pixel;
+                prev = pixel;
+                pixels_written = 1;
+            }
+            QOI_OP_LITERAL => {
+                let raw = &data[pos..pos + channels];
+                pos += channels;
+                let pixel = expand_to_rgba(raw, channels);
+                out[produced * channels..(produced + 1) * channels].copy_from_slice(raw);
+                table[qoi_hash(pixel)] = pixel;
+                prev = pixel;
+                pixels_written = 1;
+            }
+            _ => unreachable!("op_len match above already filtered unknown tags"),
+        }
+
+        produced += pixels_written;
+    }
+
+    (out, produced)
+}
+
+// --- Adaptive PNG-style scanline prefilter ----------------------------
+//
+// Borrowed from PNG/oxipng: for each row, try the five standard predictors
+// and keep whichever minimizes the sum of absolute signed residuals. A
+// one-byte filter-type tag is prepended to every row so decode can invert
+// exactly the predictor that was chosen for it.
+
+const ROW_FILTER_NONE: u8 = 0;
+const ROW_FILTER_SUB: u8 = 1;
+const ROW_FILTER_UP: u8 = 2;
+const ROW_FILTER_AVERAGE: u8 = 3;
+const ROW_FILTER_PAETH: u8 = 4;
+
+/// PNG's Paeth predictor: picks whichever of left/above/upper-left is
+/// closest to `left + above - upper_left`.
+fn paeth_predictor(left: u8, above: u8, upper_left: u8) -> u8 {
+    let p = left as i32 + above as i32 - upper_left as i32;
+    let pa = (p - left as i32).abs();
+    let pb = (p - above as i32).abs();
+    let pc = (p - upper_left as i32).abs();
+
+    if pa <= pb && pa <= pc {
+        left
+    } else if pb <= pc {
+        above
+    } else {
+        upper_left
+    }
+}
+
+/// Sum of absolute signed residuals, used to score candidate filters.
+fn residual_cost(residuals: &[u8]) -> i64 {
+    residuals
+        .iter()
+        .map(|&b| (b as i8).unsigned_abs() as i64)
+        .sum()
+}
+
+fn row_filter_sub(row: &[u8], bpp: usize) -> Vec<u8> {
+    row.iter()
+        .enumerate()
+        .map(|(i, &x)| x.wrapping_sub(if i >= bpp { row[i - bpp] } else { 0 }))
+        .collect()
+}
+
+fn row_filter_up(row: &[u8], prev_row: &[u8]) -> Vec<u8> {
+    row.iter()
+        .enumerate()
+        .map(|(i, &x)| x.wrapping_sub(prev_row[i]))
+        .collect()
+}
+
+fn row_filter_average(row: &[u8], prev_row: &[u8], bpp: usize) -> Vec<u8> {
+    row.iter()
+        .enumerate()
+        .map(|(i, &x)| {
+            let left = if i >= bpp { row[i - bpp] as u16 } else { 0 };
+            let above = prev_row[i] as u16;
+            x.wrapping_sub(((left + above) / 2) as u8)
+        })
+        .collect()
+}
+
+fn row_filter_paeth(row: &[u8], prev_row: &[u8], bpp: usize) -> Vec<u8> {
+    row.iter()
+        .enumerate()
+        .map(|(i, &x)| {
+            let a = if i >= bpp { row[i - bpp] } else { 0 };
+            let b = prev_row[i];
+            let c = if i >= bpp { prev_row[i - bpp] } else { 0 };
+            x.wrapping_sub(paeth_predictor(a, b, c))
+        })
+        .collect()
+}
+
+/// Apply the adaptive scanline prefilter to interleaved pixel samples.
+fn scanline_filter_encode(pixels: &[u8], width: usize, channels: usize) -> Vec<u8> {
+    let bpp = channels;
+    let row_len = width * channels;
+    let zero_row = vec![0u8; row_len];
+    let mut out = Vec::with_capacity(pixels.len() + pixels.len() / row_len.max(1));
+
+    for (row_index, row) in pixels.chunks_exact(row_len).enumerate() {
+        let prev_row: &[u8] = if row_index == 0 {
+            &zero_row
+        } else {
+            &pixels[(row_index - 1) * row_len..row_index * row_len]
+        };
+
+        let candidates = [
+            (ROW_FILTER_NONE, row.to_vec()),
+            (ROW_FILTER_SUB, row_filter_sub(row, bpp)),
+            (ROW_FILTER_UP, row_filter_up(row, prev_row)),
+            (ROW_FILTER_AVERAGE, row_filter_average(row, prev_row, bpp)),
+            (ROW_FILTER_PAETH, row_filter_paeth(row, prev_row, bpp)),
+        ];
+
+        let (best_tag, best_residuals) = candidates
+            .into_iter()
+            .min_by_key(|(_, residuals)| residual_cost(residuals))
+            .expect("candidate list is non-empty");
+
+        out.push(best_tag);
+        out.extend_from_slice(&best_residuals);
+    }
+
+    out
+}
+
+/// Invert [`scanline_filter_encode`], reconstructing `width * height *
+/// channels` interleaved 8-bit samples. Fails with [`Error::Io`] rather
+/// than panicking if `data` is too short for the claimed dimensions or
+/// carries an unrecognized filter-type tag, so a corrupt `.qp` file can't
+/// crash the non-lossy decode path (see [`scanline_filter_decode_lossy`]
+/// for the tolerant twin used by `--lossy`).
+fn scanline_filter_decode(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    channels: usize,
+) -> Result<Vec<u8>, Error> {
+    let bpp = channels;
+    let row_len = width * channels;
+    let mut out = vec![0u8; row_len * height];
+
+    let mut pos = 0;
+    for row_index in 0..height {
+        if pos + 1 + row_len > data.len() {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "corrupt scanline filter stream: ran out of data",
+            )));
+        }
+
+        let tag = data[pos];
+        if !matches!(
+            tag,
+            ROW_FILTER_NONE | ROW_FILTER_SUB | ROW_FILTER_UP | ROW_FILTER_AVERAGE | ROW_FILTER_PAETH
+        ) {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("corrupt scanline filter stream: unknown filter type {}", tag),
+            )));
+        }
+        pos += 1;
+        let residuals = &data[pos..pos + row_len];
+        pos += row_len;
+
+        let row_start = row_index * row_len;
+        let (prev, rest) = out.split_at_mut(row_start);
+        let recon = &mut rest[..row_len];
+        let prev_row: &[u8] = if row_index == 0 {
+            &[]
+        } else {
+            &prev[row_start - row_len..]
+        };
+
+        for i in 0..row_len {
+            let above = if row_index == 0 { 0 } else { prev_row[i] };
+            let left = if i >= bpp { recon[i - bpp] } else { 0 };
+            let upper_left = if row_index == 0 || i < bpp {
+                0
+            } else {
+                prev_row[i - bpp]
+            };
+
+            recon[i] = match tag {
+                ROW_FILTER_NONE => residuals[i],
+                ROW_FILTER_SUB => residuals[i].wrapping_add(left),
+                ROW_FILTER_UP => residuals[i].wrapping_add(above),
+                ROW_FILTER_AVERAGE => {
+                    residuals[i].wrapping_add(((left as u16 + above as u16) / 2) as u8)
+                }
+                ROW_FILTER_PAETH => {
+                    residuals[i].wrapping_add(paeth_predictor(left, above, upper_left))
+                }
+                _ => unreachable!("tag validated above"),
+            };
+        }
+    }
+
+    Ok(out)
+}
+
+/// Truncation-tolerant variant of [`scanline_filter_decode`]: reconstructs
+/// as many complete rows as `data` contains, leaving the rest zero-filled.
+/// Returns the reconstructed buffer and the number of pixels recovered.
+fn scanline_filter_decode_lossy(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    channels: usize,
+) -> (Vec<u8>, usize) {
+    let bpp = channels;
+    let row_len = width * channels;
+    let mut out = vec![0u8; row_len * height];
+
+    let mut pos = 0;
+    let mut rows_recovered = 0;
+    for row_index in 0..height {
+        if pos + 1 + row_len > data.len() {
+            break;
+        }
+
+        let tag = data[pos];
+        pos += 1;
+        let residuals = &data[pos..pos + row_len];
+        pos += row_len;
+
+        let row_start = row_index * row_len;
+        let (prev, rest) = out.split_at_mut(row_start);
+        let recon = &mut rest[..row_len];
+        let prev_row: &[u8] = if row_index == 0 {
+            &[]
+        } else {
+            &prev[row_start - row_len..]
+        };
+
+        for i in 0..row_len {
+            let above = if row_index == 0 { 0 } else { prev_row[i] };
+            let left = if i >= bpp { recon[i - bpp] } else { 0 };
+            let upper_left = if row_index == 0 || i < bpp {
+                0
+            } else {
+                prev_row[i - bpp]
+            };
+
+            recon[i] = match tag {
+                ROW_FILTER_NONE => residuals[i],
+                ROW_FILTER_SUB => residuals[i].wrapping_add(left),
+                ROW_FILTER_UP => residuals[i].wrapping_add(above),
+                ROW_FILTER_AVERAGE => {
+                    residuals[i].wrapping_add(((left as u16 + above as u16) / 2) as u8)
+                }
+                ROW_FILTER_PAETH => {
+                    residuals[i].wrapping_add(paeth_predictor(left, above, upper_left))
+                }
+                // An unrecognized tag means we've run off the rails into
+                // corrupted data; stop here rather than propagate garbage.
+                _ => 0,
+            };
+        }
+
+        rows_recovered += 1;
+    }
+
+    (out, rows_recovered * width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic xorshift generator, so test pixel data has the kind of
+    /// local variation a prefilter actually has to work with rather than a
+    /// flat gradient every predictor handles equally well.
+    fn pseudo_random_bytes(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state & 0xFF) as u8
+            })
+            .collect()
+    }
+
+    fn sample_pixels(color_type: ColorType, width: u32, height: u32, seed: u32) -> Vec<u8> {
+        let len = width as usize
+            * height as usize
+            * color_type.channels()
+            * color_type.bytes_per_sample();
+        pseudo_random_bytes(len, seed)
+    }
+
+    #[test]
+    fn round_trips_every_color_type() {
+        let color_types = [
+            ColorType::L8,
+            ColorType::La8,
+            ColorType::Rgb8,
+            ColorType::Rgba8,
+            ColorType::L16,
+            ColorType::Rgb16,
+            ColorType::Rgba16,
+        ];
+        for (i, &color_type) in color_types.iter().enumerate() {
+            let pixels = sample_pixels(color_type, 9, 7, i as u32 + 1);
+            let encoded = encode_to_vec(&pixels, 9, 7, color_type).unwrap();
+            let (header, decoded) = decode_to_vec(&encoded).unwrap();
+            assert_eq!(header.color_type, color_type);
+            assert_eq!(decoded, pixels, "{:?} round trip mismatch", color_type);
+        }
+    }
+
+    #[test]
+    fn round_trips_every_compression_method() {
+        let methods = [
+            CompressionMethod::None,
+            CompressionMethod::Brotli,
+            CompressionMethod::Deflate,
+            CompressionMethod::Zstd,
+        ];
+        let pixels = sample_pixels(ColorType::Rgba8, 11, 5, 42);
+        for &method in &methods {
+            let options = CompressionOptions {
+                method,
+                ..CompressionOptions::default()
+            };
+            let encoded =
+                encode_to_vec_with_options(&pixels, 11, 5, ColorType::Rgba8, options).unwrap();
+            let (header, decoded) = decode_to_vec(&encoded).unwrap();
+            assert_eq!(header.compression_method(), method);
+            assert_eq!(decoded, pixels, "{:?} round trip mismatch", method);
+        }
+    }
+
+    #[test]
+    fn qoi_prefilter_round_trips() {
+        let pixels = sample_pixels(ColorType::Rgba8, 13, 6, 7);
+        let channels = ColorType::Rgba8.channels();
+        let filtered = qoi_prefilter_encode(&pixels, channels);
+        let recovered = qoi_prefilter_decode(&filtered, channels, 13 * 6).unwrap();
+        assert_eq!(recovered, pixels);
+    }
+
+    #[test]
+    fn qoi_prefilter_decode_lossy_tolerates_out_of_range_index() {
+        // QOI_OP_INDEX (0x01) followed by a byte >= QOI_HASH_TABLE_SIZE: a
+        // corrupt stream a real encoder would never produce, but the lossy
+        // decoder must not index its 64-entry table out of bounds over it.
+        let data = [QOI_OP_INDEX, 200];
+        let (_out, produced) = qoi_prefilter_decode_lossy(&data, 4, 10);
+        assert_eq!(produced, 1);
+    }
+
+    #[test]
+    fn scanline_prefilter_round_trips() {
+        let pixels = sample_pixels(ColorType::Rgb8, 17, 9, 99);
+        let channels = ColorType::Rgb8.channels();
+        let filtered = scanline_filter_encode(&pixels, 17, channels);
+        let recovered = scanline_filter_decode(&filtered, 17, 9, channels).unwrap();
+        assert_eq!(recovered, pixels);
+    }
+
+    #[test]
+    fn round_trips_tiled_same_as_single_stream() {
+        let color_type = ColorType::Rgba8;
+        let (width, height) = (12, 10);
+        let pixels = sample_pixels(color_type, width, height, 5);
+
+        let single = encode_to_vec(&pixels, width, height, color_type).unwrap();
+        let tiled = encode_to_vec_tiled(&pixels, width, height, color_type, 3).unwrap();
+
+        let (single_header, single_decoded) = decode_to_vec(&single).unwrap();
+        let (tiled_header, tiled_decoded) = decode_to_vec(&tiled).unwrap();
+
+        assert!(!single_header.is_tiled());
+        assert!(tiled_header.is_tiled());
+        assert_eq!(single_decoded, pixels);
+        assert_eq!(tiled_decoded, pixels);
+    }
+
+    #[test]
+    fn decode_tiled_rejects_stripe_length_overrunning_payload() {
+        let color_type = ColorType::Rgba8;
+        let (width, height) = (8, 8);
+        let pixels = sample_pixels(color_type, width, height, 3);
+        let mut tiled = encode_to_vec_tiled(&pixels, width, height, color_type, 4).unwrap();
+
+        // Corrupt the first stripe length (right after the base header's
+        // tile_rows + stripe_count fields) to claim more bytes than the
+        // payload actually has.
+        let corrupt_at = BASE_HEADER_LEN + 8;
+        tiled[corrupt_at..corrupt_at + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        match decode_to_vec(&tiled) {
+            Err(Error::Io(_)) => {}
+            other => panic!(
+                "expected Error::Io for an overrunning stripe length, got {:?}",
+                other
+            ),
+        }
+    }
+
+    #[test]
+    fn header_parse_rejects_mismatched_stripe_count() {
+        let color_type = ColorType::Rgba8;
+        let (width, height) = (8, 8);
+        let pixels = sample_pixels(color_type, width, height, 11);
+        let mut tiled = encode_to_vec_tiled(&pixels, width, height, color_type, 4).unwrap();
+
+        // A 4-row tiling of an 8-row image needs 2 stripes; shrinking
+        // tile_rows to 2 without touching the (still 2-entry) stripe index
+        // means the index no longer matches what a 2-row tiling requires.
+        let tile_rows_at = BASE_HEADER_LEN;
+        tiled[tile_rows_at..tile_rows_at + 4].copy_from_slice(&2u32.to_be_bytes());
+
+        match decode_to_vec(&tiled) {
+            Err(Error::Io(_)) => {}
+            other => panic!(
+                "expected Error::Io for a mismatched stripe count, got {:?}",
+                other
+            ),
+        }
+    }
+}
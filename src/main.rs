@@ -1,11 +1,29 @@
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fs;
 use std::path::PathBuf;
 
-use brotli::enc::BrotliEncoderParams;
-use brotli::{BrotliCompress, BrotliDecompress};
-use clap::{Parser, Subcommand};
-use image::{DynamicImage, ImageBuffer, ImageFormat};
+use clap::{Parser, Subcommand, ValueEnum};
+use image::{DynamicImage, GenericImageView, ImageBuffer, ImageFormat, Luma, LumaA, Rgb, Rgba};
+use qp::{ColorType, CompressionMethod, CompressionOptions};
+
+/// Compression codec to store a QP file's payload with.
+#[derive(Clone, Copy, ValueEnum)]
+enum Method {
+    Brotli,
+    Deflate,
+    Zstd,
+    None,
+}
+
+impl From<Method> for CompressionMethod {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::Brotli => CompressionMethod::Brotli,
+            Method::Deflate => CompressionMethod::Deflate,
+            Method::Zstd => CompressionMethod::Zstd,
+            Method::None => CompressionMethod::None,
+        }
+    }
+}
 
 /// QP Image Tool
 #[derive(Parser)]
@@ -24,6 +42,28 @@ enum Commands {
         input: PathBuf,
         /// Output QP file path
         output: PathBuf,
+        /// Split the image into horizontal stripes of this many rows and
+        /// compress each stripe independently, so decode can decompress
+        /// stripes concurrently. Omit for a single stream covering the
+        /// whole image.
+        #[arg(long, value_name = "ROWS")]
+        tile_rows: Option<u32>,
+        /// Number of threads to compress/decompress stripes with. Only
+        /// takes effect when built with the `parallel` feature; otherwise
+        /// stripes are processed sequentially regardless of this value.
+        #[arg(long, value_name = "N")]
+        threads: Option<usize>,
+        /// Compression codec to store the payload with.
+        #[arg(long, value_enum, default_value_t = Method::Brotli)]
+        method: Method,
+        /// Brotli compression level, 0 (fastest) to 11 (smallest). Ignored
+        /// by other codecs.
+        #[arg(long, default_value_t = 11, value_parser = clap::value_parser!(u8).range(0..=11))]
+        quality: u8,
+        /// Brotli window size as a power of two, 10 to 24. Ignored by
+        /// other codecs.
+        #[arg(long, default_value_t = 24, value_parser = clap::value_parser!(u8).range(10..=24))]
+        lgwin: u8,
     },
     /// Decode a QP file to an image
     Decode {
@@ -31,24 +71,44 @@ enum Commands {
         input: PathBuf,
         /// Output image file path
         output: PathBuf,
+        /// Tolerate a truncated or corrupted file, salvaging whatever
+        /// pixel data can be decoded and zero-filling the rest instead of
+        /// failing outright.
+        #[arg(long)]
+        lossy: bool,
     },
 }
 
-const MAGIC: &[u8; 4] = b"QPIM";
-const COMPRESSION_METHOD_BROTLI: u8 = 1;
-
 fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Encode { input, output } => {
-            if let Err(e) = encode_image(&input, &output) {
+        Commands::Encode {
+            input,
+            output,
+            tile_rows,
+            threads,
+            method,
+            quality,
+            lgwin,
+        } => {
+            configure_thread_pool(threads);
+            let options = CompressionOptions {
+                method: method.into(),
+                quality,
+                lgwin,
+            };
+            if let Err(e) = encode_image(&input, &output, tile_rows, options) {
                 eprintln!("Error encoding image: {}", e);
                 std::process::exit(1);
             }
         }
-        Commands::Decode { input, output } => {
-            if let Err(e) = decode_image(&input, &output) {
+        Commands::Decode {
+            input,
+            output,
+            lossy,
+        } => {
+            if let Err(e) = decode_image(&input, &output, lossy) {
                 eprintln!("Error decoding QP image: {}", e);
                 std::process::exit(1);
             }
@@ -56,99 +116,156 @@ fn main() {
     }
 }
 
-/// Encode a standard image to QP format
-fn encode_image(input_path: &PathBuf, output_path: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+/// Pick the minimal color type an image actually needs, and return its raw
+/// pixel bytes in that layout.
+///
+/// This mirrors the spirit of `image::ColorType::has_color` /
+/// `has_alpha`: an opaque grayscale source shouldn't be inflated to RGBA
+/// before compression just because that's the easiest common format to
+/// decode back into.
+fn detect_color_type(img: &DynamicImage) -> (ColorType, Vec<u8>) {
+    let is_16_bit = matches!(
+        img,
+        DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_)
+    );
+
+    if is_16_bit {
+        let rgba16 = img.to_rgba16();
+        let has_alpha = rgba16.pixels().any(|p| p.0[3] != u16::MAX);
+        let has_color = rgba16
+            .pixels()
+            .any(|p| p.0[0] != p.0[1] || p.0[1] != p.0[2]);
+
+        // There is no 16-bit luma+alpha variant in our registry, so a
+        // grayscale-with-alpha source is promoted straight to Rgba16.
+        let color_type = match (has_color, has_alpha) {
+            (false, false) => ColorType::L16,
+            (true, false) => ColorType::Rgb16,
+            _ => ColorType::Rgba16,
+        };
+
+        let raw = match color_type {
+            ColorType::L16 => rgba16.pixels().flat_map(|p| p.0[0].to_be_bytes()).collect(),
+            ColorType::Rgb16 => rgba16
+                .pixels()
+                .flat_map(|p| [p.0[0], p.0[1], p.0[2]])
+                .flat_map(|c| c.to_be_bytes())
+                .collect(),
+            ColorType::Rgba16 => rgba16
+                .pixels()
+                .flat_map(|p| p.0)
+                .flat_map(|c| c.to_be_bytes())
+                .collect(),
+            _ => unreachable!("16-bit detection only yields 16-bit color types"),
+        };
+
+        return (color_type, raw);
+    }
+
+    let rgba8 = img.to_rgba8();
+    let has_alpha = rgba8.pixels().any(|p| p.0[3] != 255);
+    let has_color = rgba8.pixels().any(|p| p.0[0] != p.0[1] || p.0[1] != p.0[2]);
+
+    let color_type = match (has_color, has_alpha) {
+        (false, false) => ColorType::L8,
+        (false, true) => ColorType::La8,
+        (true, false) => ColorType::Rgb8,
+        (true, true) => ColorType::Rgba8,
+    };
+
+    let raw = match color_type {
+        ColorType::L8 => rgba8.pixels().map(|p| p.0[0]).collect(),
+        ColorType::La8 => rgba8.pixels().flat_map(|p| [p.0[0], p.0[3]]).collect(),
+        ColorType::Rgb8 => rgba8
+            .pixels()
+            .flat_map(|p| [p.0[0], p.0[1], p.0[2]])
+            .collect(),
+        ColorType::Rgba8 => rgba8.into_raw(),
+        _ => unreachable!("8-bit detection only yields 8-bit color types"),
+    };
+
+    (color_type, raw)
+}
+
+/// Encode a standard image to QP format. When `tile_rows` is given, the
+/// image is split into independently-compressed horizontal stripes (see
+/// [`qp::encode_to_vec_tiled_with_options`]) instead of one single stream.
+fn encode_image(
+    input_path: &PathBuf,
+    output_path: &PathBuf,
+    tile_rows: Option<u32>,
+    options: CompressionOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
     // Load the image
-    let img = image::open(input_path)?.to_rgba8();
+    let img = image::open(input_path)?;
     let (width, height) = img.dimensions();
-    let channels = 4; // RGBA
-
-    // Get raw pixel data
-    let pixel_data = img.into_raw();
-
-    // Configure Brotli encoder parameters for better compression
-    let mut params = BrotliEncoderParams::default();
-    params.quality = 11; // Maximum compression level (0-11)
-    params.lgwin = 24; // Maximum window size (10-24)
-    // params.lgblock = 0; // Use default block size (if applicable)
-    // Removed unsupported fields:
-    // params.disable_literal_context_modeling = false;
-    // params.enable_transforms = true;
-    // params.transform_bits = 16; 
-    // params.enable_dictionary = false;
-
-    // Compress the pixel data using Brotli with the configured parameters
-    let mut compressed_data = Vec::new();
-    BrotliCompress(&mut &pixel_data[..], &mut compressed_data, &params)?;
-
-    // Create the header
-    let mut header = Vec::new();
-    header.extend_from_slice(MAGIC); // Magic number
-    header.extend_from_slice(&width.to_be_bytes()); // Width
-    header.extend_from_slice(&height.to_be_bytes()); // Height
-    header.push(channels as u8); // Channels
-    header.push(COMPRESSION_METHOD_BROTLI); // Compression method
-
-    // Write header and compressed data to the output file
-    let mut output_file = File::create(output_path)?;
-    output_file.write_all(&header)?;
-    output_file.write_all(&compressed_data)?;
+
+    // Pick the smallest color layout that faithfully represents the source.
+    let (color_type, pixel_data) = detect_color_type(&img);
+
+    let encoded = match tile_rows {
+        Some(rows) => qp::encode_to_vec_tiled_with_options(
+            &pixel_data,
+            width,
+            height,
+            color_type,
+            rows,
+            options,
+        )?,
+        None => qp::encode_to_vec_with_options(&pixel_data, width, height, color_type, options)?,
+    };
+    fs::write(output_path, encoded)?;
 
     println!("Image encoded to {:?} successfully.", output_path);
     Ok(())
 }
 
-/// Decode a QP image to a standard image format
-fn decode_image(input_path: &PathBuf, output_path: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-    let mut input_file = File::open(input_path)?;
-
-    // Read the header (4 + 4 + 4 + 1 + 1 = 14 bytes)
-    let mut header = [0u8; 14];
-    input_file.read_exact(&mut header)?;
-
-    // Parse the header
-    if &header[0..4] != MAGIC {
-        return Err("Invalid QP image file: Incorrect magic number.".into());
+/// Size the global rayon thread pool from `--threads`, when the `parallel`
+/// feature is compiled in. Without that feature, stripes are always
+/// processed sequentially and this is a no-op.
+fn configure_thread_pool(threads: Option<usize>) {
+    #[cfg(feature = "parallel")]
+    if let Some(threads) = threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("global rayon thread pool is only configured once, at startup");
     }
 
-    let width = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
-    let height = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
-    let channels = header[12];
-    let compression_method = header[13];
-
-    if compression_method != COMPRESSION_METHOD_BROTLI {
-        return Err(format!(
-            "Unsupported compression method: {}",
-            compression_method
-        )
-        .into());
+    #[cfg(not(feature = "parallel"))]
+    if threads.is_some() {
+        eprintln!("Warning: --threads has no effect; this binary was built without the \"parallel\" feature.");
     }
+}
 
-    // Read the rest of the file (compressed data)
-    let mut compressed_data = Vec::new();
-    input_file.read_to_end(&mut compressed_data)?;
-
-    // Decompress the pixel data using Brotli
-    let mut decompressed_data = Vec::new();
-    BrotliDecompress(&mut &compressed_data[..], &mut decompressed_data)?;
-
-    // Reconstruct the image
-    let img = ImageBuffer::from_raw(width, height, decompressed_data.clone())
-        .ok_or("Failed to reconstruct image from pixel data.")?;
-
-    let dynamic_image = match channels {
-        3 => {
-            // If the image was RGB
-            let rgb_data = convert_rgba_to_rgb(&decompressed_data);
-            DynamicImage::ImageRgb8(
-                ImageBuffer::from_raw(width, height, rgb_data)
-                    .ok_or("Failed to create RGB image.")?,
-            )
-        }
-        4 => DynamicImage::ImageRgba8(img),
-        _ => return Err("Unsupported number of channels.".into()),
+/// Decode a QP file to a standard image format
+fn decode_image(
+    input_path: &PathBuf,
+    output_path: &PathBuf,
+    lossy: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let data = fs::read(input_path)?;
+
+    let (header, raw_data) = if lossy {
+        let (header, raw_data, pixels_recovered) = qp::decode_to_vec_lossy(&data)?;
+        let total_pixels = (header.width as usize) * (header.height as usize);
+        eprintln!(
+            "Warning: lossy decode recovered {} of {} pixels from a truncated stream.",
+            pixels_recovered, total_pixels
+        );
+        (header, raw_data)
+    } else {
+        qp::decode_to_vec(&data)?
     };
 
+    // Reconstruct the image directly in its original color type, rather
+    // than guessing a layout from a bare channel count.
+    let dynamic_image =
+        reconstruct_image(header.width, header.height, header.color_type, raw_data)?;
+
     // Determine the output format based on the file extension
     let output_format = match output_path
         .extension()
@@ -173,11 +290,55 @@ fn decode_image(input_path: &PathBuf, output_path: &PathBuf) -> Result<(), Box<d
     Ok(())
 }
 
-/// Helper function to convert RGBA data to RGB by removing the alpha channel
-fn convert_rgba_to_rgb(rgba_data: &[u8]) -> Vec<u8> {
-    rgba_data
-        .chunks(4)
-        .flat_map(|chunk| chunk.iter().take(3))
-        .cloned()
+/// Rebuild a `DynamicImage` of the given color type from raw sample bytes.
+fn reconstruct_image(
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    data: Vec<u8>,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let bad_data = || "Failed to reconstruct image from pixel data.";
+
+    Ok(match color_type {
+        ColorType::L8 => DynamicImage::ImageLuma8(
+            ImageBuffer::<Luma<u8>, _>::from_raw(width, height, data).ok_or_else(bad_data)?,
+        ),
+        ColorType::La8 => DynamicImage::ImageLumaA8(
+            ImageBuffer::<LumaA<u8>, _>::from_raw(width, height, data).ok_or_else(bad_data)?,
+        ),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(
+            ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, data).ok_or_else(bad_data)?,
+        ),
+        ColorType::Rgba8 => DynamicImage::ImageRgba8(
+            ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, data).ok_or_else(bad_data)?,
+        ),
+        ColorType::L16 => {
+            let samples = bytes_to_u16(&data);
+            DynamicImage::ImageLuma16(
+                ImageBuffer::<Luma<u16>, _>::from_raw(width, height, samples)
+                    .ok_or_else(bad_data)?,
+            )
+        }
+        ColorType::Rgb16 => {
+            let samples = bytes_to_u16(&data);
+            DynamicImage::ImageRgb16(
+                ImageBuffer::<Rgb<u16>, _>::from_raw(width, height, samples)
+                    .ok_or_else(bad_data)?,
+            )
+        }
+        ColorType::Rgba16 => {
+            let samples = bytes_to_u16(&data);
+            DynamicImage::ImageRgba16(
+                ImageBuffer::<Rgba<u16>, _>::from_raw(width, height, samples)
+                    .ok_or_else(bad_data)?,
+            )
+        }
+    })
+}
+
+/// Reinterpret a big-endian byte stream as 16-bit samples.
+fn bytes_to_u16(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
         .collect()
 }